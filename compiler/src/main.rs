@@ -1,12 +1,12 @@
 extern crate getopts;
 extern crate byteorder;
-extern crate crossbeam;
 
-use std::{io, env, process};
-use std::io::{Read, Write, Seek, SeekFrom, BufReader, Cursor};
+use std::{io, env, cmp, process, thread};
+use std::io::{Read, Write, Seek, SeekFrom, BufReader, BufWriter, Cursor};
 use std::fs::{File, OpenOptions};
 use std::num::ParseIntError;
 use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+use std::time::{Duration, Instant};
 use getopts::{Options, Matches};
 use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
 
@@ -18,6 +18,21 @@ enum CmdArgsError {
     NoCalcCacheFileProvided,
     InvalidDivStartValue(String, ParseIntError),
     InvalidThreadsValue(String, ParseIntError),
+    ZeroThreads,
+    InvalidThreadsStackSizeValue(String, ParseIntError),
+    ZeroThreadsStackSize,
+    QuietConflictsWithReportJson,
+    InvalidErrorFormatValue(String),
+    InvalidOutputAlignValue(String, ParseIntError),
+    OutputAlignNotPowerOfTwo(usize),
+    InvalidMaxBytesOutputValue(String, ParseIntError),
+    OutputTooLarge(usize, usize),
+    BytesSizeOverflow(String),
+    InvalidReadBufferSizeValue(String, ParseIntError),
+    ZeroReadBufferSize,
+    InvalidWriteBufferSizeValue(String, ParseIntError),
+    ZeroWriteBufferSize,
+    InvalidRetryOnIoValue(String, ParseIntError),
 }
 
 #[derive(Debug)]
@@ -26,12 +41,104 @@ enum Error {
     InDbOpen(io::Error),
     InDbMeta(io::Error),
     InDbSeek(io::Error),
-    InDbRead(io::Error),
+    InDbReadAt(usize, io::Error),
     OutDbCreate(io::Error),
     OutDbWrite(io::Error),
+    OutDbSync(io::Error),
     CacheOpen(io::Error),
     CacheRead(io::Error),
     CacheWrite(io::Error),
+    ThreadSpawn(io::Error),
+    ChecksumWrite(io::Error),
+    ChecksumSync(io::Error),
+    ChecksumRead(io::Error),
+    InvalidChecksumFile(String),
+    ChecksumMismatch(u32, u32),
+    OutDbVerify(io::Error),
+    OutDbVerifyMismatch(usize, u16, u16),
+    OutDbVerifyPadding(usize),
+    SelftestMismatch(usize, i32, i32),
+}
+
+impl Error {
+    fn to_json(&self) -> String {
+        let (code, category, message, value): (&str, &str, String, Option<String>) = match *self {
+            Error::CmdArgs(ref e) => match *e {
+                CmdArgsError::Getopts(ref e) =>
+                    ("getopts", "cmd_args", e.to_string(), None),
+                CmdArgsError::NoInDbFileProvided =>
+                    ("no_in_db_file_provided", "cmd_args", "no --db-in file provided".to_string(), None),
+                CmdArgsError::NoOutDbFileProvided =>
+                    ("no_out_db_file_provided", "cmd_args", "no --db-out file provided".to_string(), None),
+                CmdArgsError::NoCalcCacheFileProvided =>
+                    ("no_calc_cache_file_provided", "cmd_args", "no --calc-cache file provided".to_string(), None),
+                CmdArgsError::InvalidDivStartValue(ref v, ref e) =>
+                    ("invalid_div_start_value", "cmd_args", format!("invalid --div-start value: {}", e), Some(v.clone())),
+                CmdArgsError::InvalidThreadsValue(ref v, ref e) =>
+                    ("invalid_threads_value", "cmd_args", format!("invalid --threads value: {}", e), Some(v.clone())),
+                CmdArgsError::ZeroThreads =>
+                    ("zero_threads", "cmd_args", "--threads should be greater than zero".to_string(), None),
+                CmdArgsError::InvalidThreadsStackSizeValue(ref v, ref e) =>
+                    ("invalid_threads_stack_size_value", "cmd_args", format!("invalid --threads-stack-size value: {}", e), Some(v.clone())),
+                CmdArgsError::ZeroThreadsStackSize =>
+                    ("zero_threads_stack_size", "cmd_args", "--threads-stack-size should be greater than zero".to_string(), None),
+                CmdArgsError::QuietConflictsWithReportJson =>
+                    ("quiet_conflicts_with_report_json", "cmd_args", "--quiet and --report-json are mutually exclusive".to_string(), None),
+                CmdArgsError::InvalidErrorFormatValue(ref v) =>
+                    ("invalid_error_format_value", "cmd_args", "--error-format should be either human or json".to_string(), Some(v.clone())),
+                CmdArgsError::InvalidOutputAlignValue(ref v, ref e) =>
+                    ("invalid_output_align_value", "cmd_args", format!("invalid --output-align value: {}", e), Some(v.clone())),
+                CmdArgsError::OutputAlignNotPowerOfTwo(align) =>
+                    ("output_align_not_power_of_two", "cmd_args", "--output-align should be a power of two".to_string(), Some(align.to_string())),
+                CmdArgsError::InvalidMaxBytesOutputValue(ref v, ref e) =>
+                    ("invalid_max_bytes_output_value", "cmd_args", format!("invalid --max-bytes-output value: {}", e), Some(v.clone())),
+                CmdArgsError::OutputTooLarge(size, max_size) =>
+                    ("output_too_large", "cmd_args", format!("output db would be {} bytes, over the --max-bytes-output limit of {}", size, max_size),
+                     Some(size.to_string())),
+                CmdArgsError::BytesSizeOverflow(ref v) =>
+                    ("bytes_size_overflow", "cmd_args", "size value does not fit in usize".to_string(), Some(v.clone())),
+                CmdArgsError::InvalidReadBufferSizeValue(ref v, ref e) =>
+                    ("invalid_read_buffer_size_value", "cmd_args", format!("invalid --read-buffer-size value: {}", e), Some(v.clone())),
+                CmdArgsError::ZeroReadBufferSize =>
+                    ("zero_read_buffer_size", "cmd_args", "--read-buffer-size should be greater than zero".to_string(), None),
+                CmdArgsError::InvalidWriteBufferSizeValue(ref v, ref e) =>
+                    ("invalid_write_buffer_size_value", "cmd_args", format!("invalid --write-buffer-size value: {}", e), Some(v.clone())),
+                CmdArgsError::ZeroWriteBufferSize =>
+                    ("zero_write_buffer_size", "cmd_args", "--write-buffer-size should be greater than zero".to_string(), None),
+                CmdArgsError::InvalidRetryOnIoValue(ref v, ref e) =>
+                    ("invalid_retry_on_io_value", "cmd_args", format!("invalid --retry-on-io value: {}", e), Some(v.clone())),
+            },
+            Error::InDbOpen(ref e) => ("in_db_open", "in_db", e.to_string(), None),
+            Error::InDbMeta(ref e) => ("in_db_meta", "in_db", e.to_string(), None),
+            Error::InDbSeek(ref e) => ("in_db_seek", "in_db", e.to_string(), None),
+            Error::InDbReadAt(word_index, ref e) =>
+                ("in_db_read_at", "in_db", format!("reading word record {}: {}", word_index, e), Some(word_index.to_string())),
+            Error::OutDbCreate(ref e) => ("out_db_create", "out_db", e.to_string(), None),
+            Error::OutDbWrite(ref e) => ("out_db_write", "out_db", e.to_string(), None),
+            Error::OutDbSync(ref e) => ("out_db_sync", "out_db", e.to_string(), None),
+            Error::CacheOpen(ref e) => ("cache_open", "cache", e.to_string(), None),
+            Error::CacheRead(ref e) => ("cache_read", "cache", e.to_string(), None),
+            Error::CacheWrite(ref e) => ("cache_write", "cache", e.to_string(), None),
+            Error::ThreadSpawn(ref e) => ("thread_spawn", "thread", e.to_string(), None),
+            Error::ChecksumWrite(ref e) => ("checksum_write", "checksum", e.to_string(), None),
+            Error::ChecksumSync(ref e) => ("checksum_sync", "checksum", e.to_string(), None),
+            Error::ChecksumRead(ref e) => ("checksum_read", "checksum", e.to_string(), None),
+            Error::InvalidChecksumFile(ref v) =>
+                ("invalid_checksum_file", "checksum", "checksum file should contain a hex crc32 value".to_string(), Some(v.clone())),
+            Error::ChecksumMismatch(expected, actual) =>
+                ("checksum_mismatch", "checksum", format!("expected crc32 {:08x}, got {:08x}", expected, actual), None),
+            Error::OutDbVerify(ref e) => ("out_db_verify", "out_db", e.to_string(), None),
+            Error::OutDbVerifyMismatch(index, expected, actual) =>
+                ("out_db_verify_mismatch", "out_db", format!("entry {} reads back as {} instead of {}", index, actual, expected), Some(index.to_string())),
+            Error::OutDbVerifyPadding(padding) =>
+                ("out_db_verify_padding", "out_db", format!("{} bytes of zero padding expected after entries", padding), None),
+            Error::SelftestMismatch(index, single_div, multi_div) =>
+                ("selftest_mismatch", "selftest", format!("entry {} has div {} with one thread but {} with more", index, single_div, multi_div),
+                 Some(index.to_string())),
+        };
+        format!("{{\"code\":{},\"category\":{},\"message\":{},\"value\":{}}}",
+                json_escape(code), json_escape(category), json_escape(&message), value.as_ref().map(|v| json_escape(v)).unwrap_or("null".to_string()))
+    }
 }
 
 fn entrypoint(maybe_matches: getopts::Result) -> Result<(), Error> {
@@ -39,174 +146,640 @@ fn entrypoint(maybe_matches: getopts::Result) -> Result<(), Error> {
     run(matches)
 }
 
-fn run(matches: Matches) -> Result<(), Error> {
-    let in_db_filename = try!(matches.opt_str("db-in").ok_or(Error::CmdArgs(CmdArgsError::NoInDbFileProvided)));
-    let out_db_filename = try!(matches.opt_str("db-out").ok_or(Error::CmdArgs(CmdArgsError::NoOutDbFileProvided)));
-    let calc_cache_filename = try!(matches.opt_str("calc-cache").ok_or(Error::CmdArgs(CmdArgsError::NoCalcCacheFileProvided)));
-    let threads_count: usize = {
-        let threads_str = matches.opt_str("threads").unwrap_or("4".to_string());
-        try!(threads_str.parse().map_err(|e| Error::CmdArgs(CmdArgsError::InvalidThreadsValue(threads_str, e))))
-    };
-    let div_start: usize = {
-        let div_start_str = matches.opt_str("div-start").unwrap_or("1".to_string());
-        try!(div_start_str.parse().map_err(|e| Error::CmdArgs(CmdArgsError::InvalidDivStartValue(div_start_str, e))))
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+struct RunSummary<'a> {
+    in_db_filename: &'a str,
+    in_db_size: u64,
+    words_count: usize,
+    out_db_filename: &'a str,
+    calc_cache_filename: &'a str,
+    threads_count: usize,
+    div_start: usize,
+    divs_found: &'a [i32],
+    min_div: i32,
+    max_div: i32,
+    elapsed_ms: u128,
+}
+
+impl<'a> RunSummary<'a> {
+    fn to_json(&self) -> String {
+        let zero_divs = self.divs_found.iter().filter(|&&div| div == 0).count();
+        format!("{{\"in_db\":{},\"in_db_size\":{},\"words\":{},\"out_db\":{},\"calc_cache\":{},\"threads\":{},\"div_start\":{},\
+                 \"divs\":{},\"zero_divs\":{},\"min_div\":{},\"max_div\":{},\"elapsed_ms\":{}}}",
+                json_escape(self.in_db_filename), self.in_db_size, self.words_count, json_escape(self.out_db_filename),
+                json_escape(self.calc_cache_filename), self.threads_count, self.div_start, self.divs_found.len(), zero_divs,
+                self.min_div, self.max_div, self.elapsed_ms)
+    }
+}
+
+fn parse_bytes_size<F>(s: String, invalid_value: F) -> Result<usize, Error> where F: FnOnce(String, ParseIntError) -> CmdArgsError {
+    let (value, multiplier) = {
+        let (digits, multiplier) = match s.chars().last() {
+            Some('k') | Some('K') => (&s[.. s.len() - 1], 1 << 10),
+            Some('m') | Some('M') => (&s[.. s.len() - 1], 1 << 20),
+            Some('g') | Some('G') => (&s[.. s.len() - 1], 1 << 30),
+            _ => (&s[..], 1),
+        };
+        (digits.parse::<usize>(), multiplier)
     };
+    match value {
+        Ok(value) =>
+            value.checked_mul(multiplier).ok_or(Error::CmdArgs(CmdArgsError::BytesSizeOverflow(s))),
+        Err(e) =>
+            Err(Error::CmdArgs(invalid_value(s, e))),
+    }
+}
 
-    let in_db = try!(File::open(&in_db_filename).map_err(Error::InDbOpen));
-    let metadata = try!(in_db.metadata().map_err(Error::InDbMeta));
-    let in_db_size = metadata.len();
-    let words_count = (in_db_size / 45000 / 4) as usize;
-    let mut in_db_r = BufReader::new(in_db);
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0 .. 8 {
+            crc = if crc & 1 == 0 { crc >> 1 } else { (crc >> 1) ^ 0xEDB88320 };
+        }
+    }
+    !crc
+}
 
-    let mut cache = try!(OpenOptions::new().read(true).write(true).create(true).open(&calc_cache_filename).map_err(Error::CacheOpen));
-    
-    println!("Running: in_db_filename = {} (size = {}, words = {}), out_db_filename = {}, calc_cache_filename = {}, threads_count = {}, div_start = {}",
-             in_db_filename, in_db_size, words_count, out_db_filename, calc_cache_filename, threads_count, div_start);
+fn checksum_filename(db_filename: &str) -> String {
+    format!("{}.sum", db_filename)
+}
 
-    let mut divs_found = Vec::new();
-    let mut min_div = std::i32::MAX;
-    let mut max_div = -1;
+fn write_checksum_file(db_filename: &str, checksum: u32, fsync: bool) -> Result<(), Error> {
+    let mut sum = try!(File::create(checksum_filename(db_filename)).map_err(Error::ChecksumWrite));
+    try!(writeln!(sum, "{:08x}", checksum).map_err(Error::ChecksumWrite));
+    if fsync {
+        try!(sum.sync_all().map_err(Error::ChecksumSync));
+    }
+    Ok(())
+}
 
-    let chunk_size = 8000;
-    let chunk_limit = 32000 / chunk_size;
+fn verify_checksum(db_filename: &str, quiet: bool, report_json: bool) -> Result<(), Error> {
+    let mut data = Vec::new();
+    try!(File::open(db_filename).and_then(|mut db| db.read_to_end(&mut data)).map_err(Error::ChecksumRead));
+    let mut sum_str = String::new();
+    try!(File::open(checksum_filename(db_filename)).and_then(|mut sum| sum.read_to_string(&mut sum_str)).map_err(Error::ChecksumRead));
+    let expected = try!(u32::from_str_radix(sum_str.trim(), 16).map_err(|_| Error::InvalidChecksumFile(sum_str.trim().to_string())));
+    let actual = crc32_update(0, &data);
+    if expected != actual {
+        return Err(Error::ChecksumMismatch(expected, actual));
+    }
+    if report_json {
+        println!("{{\"db\":{},\"crc32\":\"{:08x}\",\"checksum_ok\":true}}", json_escape(db_filename), actual);
+    } else if !quiet {
+        println!("Checksum OK: {} crc32 = {:08x}", db_filename, actual);
+    }
+    Ok(())
+}
 
-    let mut read_buf: Vec<u8> = (0 .. 45000 * 4).map(|_| 0).collect();
+fn create_versioned(db_filename: &str) -> Result<(String, File), Error> {
+    let mut version = 1;
+    loop {
+        let versioned_filename = format!("{}.{}", db_filename, version);
+        match OpenOptions::new().write(true).create_new(true).open(&versioned_filename) {
+            Ok(file) =>
+                return Ok((versioned_filename, file)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists =>
+                version += 1,
+            Err(e) =>
+                return Err(Error::OutDbCreate(e)),
+        }
+    }
+}
 
-    for chunk_index in 0 .. chunk_limit {
-        println!(" ;; READ chunk_index = {}/{} by {}, current min = {}, max = {}, divs_found = {}",
-                 chunk_index, chunk_limit, chunk_size, min_div, max_div, divs_found.len());
+fn output_padding(out_db_size: usize, output_align: usize) -> usize {
+    (output_align - out_db_size % output_align) % output_align
+}
 
-        let mut seed_sample = Vec::with_capacity(chunk_size * words_count);
+fn check_output_size(out_db_size: usize, output_align: usize, max_bytes_output: usize) -> Result<usize, Error> {
+    let padding = output_padding(out_db_size, output_align);
+    let total_size = out_db_size + padding;
+    if total_size > max_bytes_output {
+        return Err(Error::CmdArgs(CmdArgsError::OutputTooLarge(total_size, max_bytes_output)));
+    }
+    Ok(padding)
+}
 
-        try!(in_db_r.seek(SeekFrom::Start(0u64)).map_err(Error::InDbSeek));
-        for _ in 0 .. words_count {
-            try!(in_db_r.read_exact(&mut read_buf).map_err(Error::InDbRead));
-            let mut curr = Cursor::new(&read_buf);
-            let offset = chunk_index as u64 * chunk_size as u64;
-            try!(curr.seek(SeekFrom::Start(offset * 4)).map_err(Error::InDbSeek));
-            for _chunk in 0 .. chunk_size {
-                let hash = try!(curr.read_i32::<NativeEndian>().map_err(Error::InDbRead));
-                seed_sample.push(hash);
-            }
+fn write_out_db<W>(out_db: &mut W, out_values: &[u16], padding: usize) -> Result<u32, Error> where W: Write {
+    let mut checksum = 0;
+    for &value in out_values.iter() {
+        try!(out_db.write_u16::<NativeEndian>(value).map_err(Error::OutDbWrite));
+        checksum = crc32_update(checksum, &value.to_ne_bytes());
+    }
+    let zeros = [0u8; 8192];
+    let mut padding_left = padding;
+    while padding_left > 0 {
+        let zeros_chunk = &zeros[.. cmp::min(padding_left, zeros.len())];
+        try!(out_db.write_all(zeros_chunk).map_err(Error::OutDbWrite));
+        checksum = crc32_update(checksum, zeros_chunk);
+        padding_left -= zeros_chunk.len();
+    }
+    try!(out_db.flush().map_err(Error::OutDbWrite));
+    Ok(checksum)
+}
+
+fn verify_out_db(db_filename: &str, out_values: &[u16], padding: usize) -> Result<(), Error> {
+    let mut written = BufReader::new(try!(File::open(db_filename).map_err(Error::OutDbVerify)));
+    for (index, &value) in out_values.iter().enumerate() {
+        let stored = try!(written.read_u16::<NativeEndian>().map_err(Error::OutDbVerify));
+        if stored != value {
+            return Err(Error::OutDbVerifyMismatch(index, value, stored));
+        }
+    }
+    let mut trailing = 0;
+    let mut trailing_buf = [0u8; 8192];
+    loop {
+        let bytes_read = try!(written.read(&mut trailing_buf).map_err(Error::OutDbVerify));
+        if bytes_read == 0 {
+            break;
+        }
+        trailing += bytes_read;
+        if trailing > padding || trailing_buf[.. bytes_read].iter().any(|&byte| byte != 0) {
+            return Err(Error::OutDbVerifyPadding(padding));
+        }
+    }
+    if trailing != padding {
+        return Err(Error::OutDbVerifyPadding(padding));
+    }
+    Ok(())
+}
+
+fn retry_io<T, F>(retries: u32, backoff_ms: u64, what: &str, quiet: bool, mut attempt_fn: F) -> Result<T, Error>
+    where F: FnMut() -> Result<T, Error>
+{
+    let mut attempt = 0;
+    loop {
+        match attempt_fn() {
+            Ok(value) =>
+                return Ok(value),
+            Err(e) => {
+                if attempt >= retries {
+                    return Err(e);
+                }
+                attempt += 1;
+                if !quiet {
+                    println!(" ;; RETRY {} {}/{} after error: {:?}", what, attempt, retries, e);
+                }
+                thread::sleep(Duration::from_millis(backoff_ms << cmp::min(attempt, 6)));
+            },
+        }
+    }
+}
+
+fn write_progress_json<W>(out: &mut W, divs_done: usize, divs_total: usize, elapsed_ms: u128) -> io::Result<()> where W: Write {
+    writeln!(out, "{{\"divs_done\":{},\"divs_total\":{},\"elapsed_ms\":{}}}", divs_done, divs_total, elapsed_ms)
+}
+
+fn read_seed_sample<R>(in_db_r: &mut R, read_buf: &mut [u8], words_count: usize, chunk_index: usize, chunk_size: usize) -> Result<Vec<i32>, Error>
+    where R: Read + Seek
+{
+    let mut seed_sample = Vec::with_capacity(chunk_size * words_count);
+
+    try!(in_db_r.seek(SeekFrom::Start(0u64)).map_err(Error::InDbSeek));
+    for word_index in 0 .. words_count {
+        try!(in_db_r.read_exact(read_buf).map_err(|e| Error::InDbReadAt(word_index, e)));
+        let mut curr = Cursor::new(&*read_buf);
+        let offset = chunk_index as u64 * chunk_size as u64;
+        try!(curr.seek(SeekFrom::Start(offset * 4)).map_err(Error::InDbSeek));
+        for _chunk in 0 .. chunk_size {
+            let hash = try!(curr.read_i32::<NativeEndian>().map_err(|e| Error::InDbReadAt(word_index, e)));
+            seed_sample.push(hash);
         }
+    }
+    Ok(seed_sample)
+}
 
-        for chunk in 0 .. chunk_size {
+struct DivSearch {
+    words_count: usize,
+    record_len: usize,
+    chunk_size: usize,
+    chunk_limit: usize,
+    threads_count: usize,
+    threads_stack_size: Option<usize>,
+    div_start: usize,
+    quiet: bool,
+}
 
-            if chunk % 50 == 0 {
-                println!(" ;; RUN chunk_index = {}/{} by {}, chunk N{}, current min = {}, max = {}, divs_found = {}",
-                         chunk_index, chunk_limit, chunk_size, chunk, min_div, max_div, divs_found.len());
+impl DivSearch {
+    fn run<R, C, P>(&self, in_db_r: &mut R, cache: &mut C, mut progress: Option<&mut P>, start_time: Instant) -> Result<(Vec<i32>, i32, i32), Error>
+        where R: Read + Seek, C: Read + Write, P: Write
+    {
+        let mut divs_found = Vec::new();
+        let mut min_div = std::i32::MAX;
+        let mut max_div = -1;
+
+        let mut read_buf: Vec<u8> = (0 .. self.record_len * 4).map(|_| 0).collect();
+
+        for chunk_index in 0 .. self.chunk_limit {
+            if !self.quiet {
+                println!(" ;; READ chunk_index = {}/{} by {}, current min = {}, max = {}, divs_found = {}",
+                         chunk_index, self.chunk_limit, self.chunk_size, min_div, max_div, divs_found.len());
             }
 
-            match cache.read_i32::<NativeEndian>() {
-                Ok(cached_div) => {
-                    if cached_div > 0 && cached_div < min_div {
-                        min_div = cached_div;
+            let seed_sample = try!(read_seed_sample(in_db_r, &mut read_buf, self.words_count, chunk_index, self.chunk_size));
+
+            for chunk in 0 .. self.chunk_size {
+
+                if !self.quiet && chunk % 50 == 0 {
+                    println!(" ;; RUN chunk_index = {}/{} by {}, chunk N{}, current min = {}, max = {}, divs_found = {}",
+                             chunk_index, self.chunk_limit, self.chunk_size, chunk, min_div, max_div, divs_found.len());
+                }
+                if chunk % 50 == 0 {
+                    if let Some(ref mut progress) = progress {
+                        let _ = write_progress_json(progress, divs_found.len(), self.chunk_limit * self.chunk_size, start_time.elapsed().as_millis());
                     }
-                    if cached_div > 0 && cached_div > max_div {
-                        max_div = cached_div;
+                }
+
+                match cache.read_i32::<NativeEndian>() {
+                    Ok(cached_div) => {
+                        if cached_div > 0 && cached_div < min_div {
+                            min_div = cached_div;
+                        }
+                        if cached_div > 0 && cached_div > max_div {
+                            max_div = cached_div;
+                        }
+                        divs_found.push(cached_div);
+                        continue;
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof =>
+                        (),
+                    Err(e) =>
+                        return Err(Error::CacheRead(e)),
+                }
+
+                let mut zero_found = false;
+                for i in 0 .. self.words_count {
+                    let hash = seed_sample[i * self.chunk_size + chunk];
+                    if hash == 0 {
+                        zero_found = true;
+                        break;
                     }
-                    divs_found.push(cached_div);
-                    continue;
-                },
-                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof =>
-                    (),
-                Err(e) =>
-                    return Err(Error::CacheRead(e)),
-            }
+                }
 
-            let mut zero_found = false;
-            for i in 0 .. words_count {
-                let hash = seed_sample[i * chunk_size + chunk];
-                if hash == 0 {
-                    zero_found = true;
-                    break;
+                if zero_found {
+                    divs_found.push(0);
+                    try!(cache.write_i32::<NativeEndian>(0).map_err(Error::CacheWrite));
+                    try!(cache.flush().map_err(Error::CacheWrite));
+                    continue;
                 }
-            }
 
-            if zero_found {
-                divs_found.push(0);
-                try!(cache.write_i32::<NativeEndian>(0).map_err(Error::CacheWrite));
-                try!(cache.flush().map_err(Error::CacheWrite));
-                continue;
-            }
+                let div = AtomicIsize::new(self.div_start as isize);
+                let pass = AtomicBool::new(false);
+                let rdiv = AtomicIsize::new(isize::MAX);
+
+                try!(thread::scope(|scope| {
+                    for _ in 0 .. self.threads_count {
+                        let mut builder = thread::Builder::new();
+                        if let Some(stack_size) = self.threads_stack_size {
+                            builder = builder.stack_size(stack_size);
+                        }
+                        try!(builder.spawn_scoped(scope, || {
+                            while !pass.load(Ordering::Relaxed) {
+                                let current_div = div.fetch_add(1, Ordering::Relaxed) as i32;
+                                // if current_div >= std::u16::MAX as i32 * 2 {
+                                //     pass.store(true, Ordering::SeqCst);
+                                //     rdiv.store(max_div as isize, Ordering::SeqCst);
+                                //     break;
+                                // }
+                                // if current_div % 1000 == 0 {
+                                //     println!(" ;; currently trying div = {}", current_div);
+                                // }
 
-            let div = AtomicIsize::new(div_start as isize);
-            let pass = AtomicBool::new(false);
-            let rdiv = AtomicIsize::new(0);
-
-            crossbeam::scope(|scope| {
-                for _ in 0 .. threads_count {
-                    scope.spawn(|| {
-                        while !pass.load(Ordering::Relaxed) {
-                            let current_div = div.fetch_add(1, Ordering::Relaxed) as i32;
-                            // if current_div >= std::u16::MAX as i32 * 2 {
-                            //     pass.store(true, Ordering::SeqCst);
-                            //     rdiv.store(max_div as isize, Ordering::SeqCst);
-                            //     break;
-                            // }
-                            // if current_div % 1000 == 0 {
-                            //     println!(" ;; currently trying div = {}", current_div);
-                            // }
-
-                            let mut found = false;
-                            for i in 0 .. words_count {
-                                let hash = seed_sample[i * chunk_size + chunk];
-                                if hash % current_div == 0 {
-                                    found = true;
-                                    break;
+                                let mut found = false;
+                                for i in 0 .. self.words_count {
+                                    let hash = seed_sample[i * self.chunk_size + chunk];
+                                    if hash % current_div == 0 {
+                                        found = true;
+                                        break;
+                                    }
                                 }
-                            }
 
-                            if !found {
-                                pass.store(true, Ordering::SeqCst);
-                                rdiv.store(current_div as isize, Ordering::SeqCst);
+                                if !found {
+                                    pass.store(true, Ordering::SeqCst);
+                                    // workers holding smaller candidates may still pass, keep the smallest
+                                    rdiv.fetch_min(current_div as isize, Ordering::SeqCst);
+                                }
                             }
-                        }
-                    });
-                }
-            });
+                        }).map_err(Error::ThreadSpawn));
+                    }
+                    Ok(())
+                }));
 
-            let result_div = rdiv.load(Ordering::Relaxed) as i32;
-            if result_div < min_div {
-                min_div = result_div;
-            }
-            if result_div > max_div {
-                max_div = result_div;
+                let result_div = rdiv.load(Ordering::Relaxed) as i32;
+                if result_div < min_div {
+                    min_div = result_div;
+                }
+                if result_div > max_div {
+                    max_div = result_div;
+                }
+                divs_found.push(result_div);
+                try!(cache.write_i32::<NativeEndian>(result_div).map_err(Error::CacheWrite));
+                try!(cache.flush().map_err(Error::CacheWrite));
             }
-            divs_found.push(result_div);
-            try!(cache.write_i32::<NativeEndian>(result_div).map_err(Error::CacheWrite));
-            try!(cache.flush().map_err(Error::CacheWrite));
         }
+
+        if let Some(progress) = progress {
+            let _ = write_progress_json(progress, divs_found.len(), self.chunk_limit * self.chunk_size, start_time.elapsed().as_millis());
+        }
+        Ok((divs_found, min_div, max_div))
+    }
+}
+
+fn selftest_search(threads_count: usize, threads_stack_size: Option<usize>) -> DivSearch {
+    DivSearch {
+        words_count: 4096,
+        record_len: 32,
+        chunk_size: 16,
+        chunk_limit: 2,
+        threads_count,
+        threads_stack_size,
+        div_start: 1,
+        quiet: true,
+    }
+}
+
+fn selftest_db(words_count: usize, record_len: usize) -> Vec<u8> {
+    let mut in_db = Vec::with_capacity(words_count * record_len * 4);
+    let mut state: u32 = 12345;
+    for _ in 0 .. words_count * record_len {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        // multiples of lcm(1 ..= 20) make 23 and 29 the first two passing candidates, tested side by side
+        let hash = ((state >> 16) as i32 % 9 + 1) * 232792560;
+        in_db.extend_from_slice(&hash.to_ne_bytes());
+    }
+    in_db
+}
+
+fn selftest(threads_count: usize, threads_stack_size: Option<usize>, quiet: bool, report_json: bool) -> Result<(), Error> {
+    let single_search = selftest_search(1, threads_stack_size);
+    let multi_search = selftest_search(threads_count, threads_stack_size);
+    let in_db = selftest_db(single_search.words_count, single_search.record_len);
+    let (single_divs, _, _) =
+        try!(single_search.run(&mut Cursor::new(&in_db), &mut Cursor::new(Vec::new()), None::<&mut io::Stderr>, Instant::now()));
+    let (multi_divs, _, _) =
+        try!(multi_search.run(&mut Cursor::new(&in_db), &mut Cursor::new(Vec::new()), None::<&mut io::Stderr>, Instant::now()));
+    for (index, (&single_div, &multi_div)) in single_divs.iter().zip(multi_divs.iter()).enumerate() {
+        if single_div != multi_div {
+            return Err(Error::SelftestMismatch(index, single_div, multi_div));
+        }
+    }
+    if report_json {
+        println!("{{\"selftest_ok\":true,\"divs\":{},\"threads\":{}}}", single_divs.len(), threads_count);
+    } else if !quiet {
+        println!("Selftest PASS: {} divs match between 1 and {} threads", single_divs.len(), threads_count);
+    }
+    Ok(())
+}
+
+fn run(matches: Matches) -> Result<(), Error> {
+    let start_time = Instant::now();
+    match matches.opt_str("error-format") {
+        None => (),
+        Some(ref format) if format == "human" || format == "json" => (),
+        Some(format) => return Err(Error::CmdArgs(CmdArgsError::InvalidErrorFormatValue(format))),
+    }
+    let report_json = matches.opt_present("report-json");
+    if report_json && matches.opt_present("quiet") {
+        return Err(Error::CmdArgs(CmdArgsError::QuietConflictsWithReportJson));
+    }
+    let quiet = matches.opt_present("quiet") || report_json;
+    if let Some(db_filename) = matches.opt_str("verify-checksum") {
+        return verify_checksum(&db_filename, quiet, report_json);
+    }
+    let threads_count: usize = {
+        let threads_str = matches.opt_str("threads").unwrap_or("4".to_string());
+        try!(threads_str.parse().map_err(|e| Error::CmdArgs(CmdArgsError::InvalidThreadsValue(threads_str, e))))
+    };
+    if threads_count == 0 {
+        return Err(Error::CmdArgs(CmdArgsError::ZeroThreads));
+    }
+    let div_start: usize = {
+        let div_start_str = matches.opt_str("div-start").unwrap_or("1".to_string());
+        try!(div_start_str.parse().map_err(|e| Error::CmdArgs(CmdArgsError::InvalidDivStartValue(div_start_str, e))))
+    };
+    let threads_stack_size = match matches.opt_str("threads-stack-size") {
+        Some(stack_size_str) =>
+            Some(try!(parse_bytes_size(stack_size_str, CmdArgsError::InvalidThreadsStackSizeValue))),
+        None =>
+            None,
+    };
+    if threads_stack_size == Some(0) {
+        return Err(Error::CmdArgs(CmdArgsError::ZeroThreadsStackSize));
+    }
+    if matches.opt_present("selftest") {
+        return selftest(threads_count, threads_stack_size, quiet, report_json);
+    }
+    let in_db_filename = try!(matches.opt_str("db-in").ok_or(Error::CmdArgs(CmdArgsError::NoInDbFileProvided)));
+    let out_db_filename = try!(matches.opt_str("db-out").ok_or(Error::CmdArgs(CmdArgsError::NoOutDbFileProvided)));
+    let calc_cache_filename = try!(matches.opt_str("calc-cache").ok_or(Error::CmdArgs(CmdArgsError::NoCalcCacheFileProvided)));
+    let read_buffer_size = {
+        let read_buffer_size_str = matches.opt_str("read-buffer-size").unwrap_or("1M".to_string());
+        try!(parse_bytes_size(read_buffer_size_str, CmdArgsError::InvalidReadBufferSizeValue))
+    };
+    if read_buffer_size == 0 {
+        return Err(Error::CmdArgs(CmdArgsError::ZeroReadBufferSize));
+    }
+    let write_buffer_size = {
+        let write_buffer_size_str = matches.opt_str("write-buffer-size").unwrap_or("64K".to_string());
+        try!(parse_bytes_size(write_buffer_size_str, CmdArgsError::InvalidWriteBufferSizeValue))
+    };
+    if write_buffer_size == 0 {
+        return Err(Error::CmdArgs(CmdArgsError::ZeroWriteBufferSize));
+    }
+    let retry_on_io: u32 = {
+        let retry_on_io_str = matches.opt_str("retry-on-io").unwrap_or("0".to_string());
+        try!(retry_on_io_str.parse().map_err(|e| Error::CmdArgs(CmdArgsError::InvalidRetryOnIoValue(retry_on_io_str, e))))
+    };
+    let output_align = match matches.opt_str("output-align") {
+        Some(align_str) =>
+            try!(parse_bytes_size(align_str, CmdArgsError::InvalidOutputAlignValue)),
+        None =>
+            1,
+    };
+    if !output_align.is_power_of_two() {
+        return Err(Error::CmdArgs(CmdArgsError::OutputAlignNotPowerOfTwo(output_align)));
+    }
+    let chunk_size: usize = 8000;
+    let chunk_limit = 32000 / chunk_size;
+    let out_db_size = chunk_limit * chunk_size * 2;
+    let max_bytes_output = {
+        let max_bytes_output_str = matches.opt_str("max-bytes-output").unwrap_or("1M".to_string());
+        try!(parse_bytes_size(max_bytes_output_str, CmdArgsError::InvalidMaxBytesOutputValue))
+    };
+    let padding = try!(check_output_size(out_db_size, output_align, max_bytes_output));
+    let out_db_fsync = matches.opt_present("out-db-fsync");
+    let output_checksum = matches.opt_present("output-checksum");
+    let verify_after_write = matches.opt_present("verify-after-write");
+    let output_versioned = matches.opt_present("output-versioned");
+    let progress_json = matches.opt_present("progress-json");
+
+    let in_db = try!(File::open(&in_db_filename).map_err(Error::InDbOpen));
+    let metadata = try!(in_db.metadata().map_err(Error::InDbMeta));
+    let in_db_size = metadata.len();
+    let words_count = (in_db_size / 45000 / 4) as usize;
+    let mut in_db_r = BufReader::with_capacity(read_buffer_size, in_db);
+
+    let mut cache = try!(OpenOptions::new().read(true).write(true).create(true).open(&calc_cache_filename).map_err(Error::CacheOpen));
+
+    if !quiet {
+        println!("Running: in_db_filename = {} (size = {}, words = {}), out_db_filename = {}, calc_cache_filename = {}, threads_count = {}, div_start = {}",
+                 in_db_filename, in_db_size, words_count, out_db_filename, calc_cache_filename, threads_count, div_start);
     }
 
-    println!("OVERALL base div = {}", min_div);
+    let div_search = DivSearch {
+        words_count,
+        record_len: 45000,
+        chunk_size,
+        chunk_limit,
+        threads_count,
+        threads_stack_size,
+        div_start,
+        quiet,
+    };
+    let mut stderr = io::stderr();
+    let progress = if progress_json { Some(&mut stderr) } else { None };
+    let (divs_found, min_div, max_div) = try!(div_search.run(&mut in_db_r, &mut cache, progress, start_time));
 
-    let mut out_db = try!(File::create(out_db_filename).map_err(Error::OutDbCreate));
-    for div in divs_found {
+    if !quiet {
+        println!("OVERALL base div = {}", min_div);
+    }
+
+    let out_values: Vec<u16> = divs_found.iter().map(|&div| {
         let value = if div == 0 {
             0
         } else {
             div - min_div + 1
         };
         assert!(value <= std::u16::MAX as i32);
-        try!(out_db.write_u16::<NativeEndian>(value as u16).map_err(Error::OutDbWrite));
+        value as u16
+    }).collect();
+
+    // the target file name is fixed by the first successful open, so retries rewrite the same versioned file
+    let mut resolved_filename: Option<String> = None;
+    let checksum = try!(retry_io(retry_on_io, 100, "output db write", quiet, || {
+        let out_db_file = match resolved_filename {
+            Some(ref filename) =>
+                try!(OpenOptions::new().write(true).create(true).truncate(true).open(filename).map_err(Error::OutDbCreate)),
+            None if output_versioned => {
+                let (versioned_filename, file) = try!(create_versioned(&out_db_filename));
+                if !quiet {
+                    println!("Writing versioned output db: {}", versioned_filename);
+                }
+                resolved_filename = Some(versioned_filename);
+                file
+            },
+            None => {
+                let file = try!(File::create(&out_db_filename).map_err(Error::OutDbCreate));
+                resolved_filename = Some(out_db_filename.clone());
+                file
+            },
+        };
+        let mut out_db = BufWriter::with_capacity(write_buffer_size, out_db_file);
+        let checksum = try!(write_out_db(&mut out_db, &out_values, padding));
+        if out_db_fsync {
+            try!(out_db.get_ref().sync_all().map_err(Error::OutDbSync));
+        }
+        Ok(checksum)
+    }));
+    let out_db_filename = resolved_filename.unwrap();
+    if verify_after_write {
+        try!(verify_out_db(&out_db_filename, &out_values, padding));
+    }
+    if output_checksum {
+        try!(retry_io(retry_on_io, 100, "checksum write", quiet, || write_checksum_file(&out_db_filename, checksum, out_db_fsync)));
+    }
+
+    if report_json {
+        let summary = RunSummary {
+            in_db_filename: &in_db_filename,
+            in_db_size,
+            words_count,
+            out_db_filename: &out_db_filename,
+            calc_cache_filename: &calc_cache_filename,
+            threads_count,
+            div_start,
+            divs_found: &divs_found,
+            min_div,
+            max_div,
+            elapsed_ms: start_time.elapsed().as_millis(),
+        };
+        println!("{}", summary.to_json());
     }
 
     Ok(())
 }
 
-fn main() {
-    let mut args = env::args();
-    let cmd_proc = args.next().unwrap();
+fn build_options() -> Options {
     let mut opts = Options::new();
 
     opts.optopt("i", "db-in", "in file for input binary data db", "INDB");
+    opts.optopt("", "read-buffer-size", "input db read buffer size in bytes, K/M/G suffixes allowed (opt, default: 1M)", "SIZE");
     opts.optopt("o", "db-out", "output file for out binary data db", "OUTDB");
     opts.optopt("c", "calc-cache", "cache file used during calculations", "CACHE");
     opts.optopt("t", "threads", "total concurrent threads to use (opt, default: 4)", "THREADS");
+    opts.optopt("", "threads-stack-size", "worker thread stack size in bytes, K/M/G suffixes allowed; only needed \
+                                           if workers overflow their stacks (opt, default: platform default)", "SIZE");
     opts.optopt("d", "div-start", "div start value (opt, default: 1)", "DIVSTART");
-    match entrypoint(opts.parse(args)) {
+    opts.optflag("", "output-versioned", "write to OUTDB.N using the first free N instead of overwriting OUTDB (opt)");
+    opts.optopt("", "write-buffer-size", "output db write buffer size in bytes, K/M/G suffixes allowed (opt, default: 64K)", "SIZE");
+    opts.optopt("", "output-align", "pad output db with zero entries to a multiple of this many bytes, a power of two, \
+                                     K/M/G suffixes allowed (opt, default: 1)", "ALIGN");
+    opts.optopt("", "max-bytes-output", "refuse to write an output db larger than this many bytes including padding, \
+                                         K/M/G suffixes allowed (opt, default: 1M)", "SIZE");
+    opts.optflag("", "out-db-fsync", "fsync output db before exiting, costs a full disk flush (opt)");
+    opts.optopt("", "retry-on-io", "retry failed output db writes this many times with backoff (opt, default: 0)", "N");
+    opts.optflag("", "verify-after-write", "read output db back and compare every entry after writing (opt)");
+    opts.optflag("", "output-checksum", "write crc32 of output db to OUTDB.sum sidecar file (opt)");
+    opts.optflag("", "selftest", "search divs for a built-in table with 1 and THREADS threads, check they agree and exit");
+    opts.optopt("", "verify-checksum", "check DB against its DB.sum sidecar file and exit", "DB");
+    opts.optflag("q", "quiet", "suppress all non-error output (opt)");
+    opts.optflag("", "report-json", "print a single json summary instead of human readable output (opt)");
+    opts.optflag("", "progress-json", "emit progress as json lines on stderr (opt)");
+    opts.optopt("", "error-format", "error output format: human or json (opt, default: human)", "FORMAT");
+    opts
+}
+
+fn json_errors_requested(args: &[String]) -> bool {
+    let mut args_iter = args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = args_iter.next() {
+        if arg == "--error-format=json" {
+            return true;
+        } else if arg == "--error-format" {
+            return args_iter.next().map(|format| format == "json").unwrap_or(false);
+        }
+    }
+    false
+}
+
+fn main() {
+    let mut args = env::args();
+    let cmd_proc = args.next().unwrap();
+    let args: Vec<String> = args.collect();
+    let opts = build_options();
+    let json_errors = json_errors_requested(&args);
+    match entrypoint(opts.parse(&args)) {
         Ok(()) => (),
+        Err(ref cause) if json_errors => {
+            let _ = writeln!(&mut io::stderr(), "{}", cause.to_json());
+            process::exit(1);
+        },
         Err(cause) => {
             let _ = writeln!(&mut io::stderr(), "Error: {:?}", cause);
             let usage = format!("Usage: {}", cmd_proc);
@@ -215,3 +788,384 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use super::*;
+
+    // Minimal parser for the flat json objects the compiler emits.
+    fn parse_flat_json(json: &str) -> HashMap<String, String> {
+        let chars: Vec<char> = json.trim().chars().collect();
+        assert_eq!(chars.first(), Some(&'{'));
+        assert_eq!(chars.last(), Some(&'}'));
+        let mut fields = HashMap::new();
+        let mut pos = 1;
+        let parse_string = |pos: &mut usize| -> String {
+            assert_eq!(chars[*pos], '"');
+            *pos += 1;
+            let mut value = String::new();
+            while chars[*pos] != '"' {
+                if chars[*pos] == '\\' {
+                    *pos += 1;
+                    match chars[*pos] {
+                        'n' => value.push('\n'),
+                        'r' => value.push('\r'),
+                        't' => value.push('\t'),
+                        'u' => {
+                            let code: String = chars[*pos + 1 .. *pos + 5].iter().cloned().collect();
+                            value.push(std::char::from_u32(u32::from_str_radix(&code, 16).unwrap()).unwrap());
+                            *pos += 4;
+                        },
+                        c => value.push(c),
+                    }
+                } else {
+                    value.push(chars[*pos]);
+                }
+                *pos += 1;
+            }
+            *pos += 1;
+            value
+        };
+        while chars[pos] != '}' {
+            let key = parse_string(&mut pos);
+            assert_eq!(chars[pos], ':');
+            pos += 1;
+            let value = if chars[pos] == '"' {
+                parse_string(&mut pos)
+            } else {
+                let start = pos;
+                while chars[pos] != ',' && chars[pos] != '}' {
+                    pos += 1;
+                }
+                let literal: String = chars[start .. pos].iter().cloned().collect();
+                assert!(literal == "null" || literal == "true" || literal == "false" || literal.parse::<f64>().is_ok(),
+                        "bad json literal {:?}", literal);
+                literal
+            };
+            assert!(fields.insert(key, value).is_none());
+            if chars[pos] == ',' {
+                pos += 1;
+            }
+        }
+        assert_eq!(pos, chars.len() - 1);
+        fields
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn json_errors_requested_from_raw_args() {
+        assert!(json_errors_requested(&args(&["--error-format", "json", "--bogus"])));
+        assert!(json_errors_requested(&args(&["--bogus", "--error-format=json"])));
+        assert!(!json_errors_requested(&args(&["--error-format", "human"])));
+        assert!(!json_errors_requested(&args(&["--error-format"])));
+        assert!(!json_errors_requested(&args(&["--", "--error-format", "json"])));
+    }
+
+    #[test]
+    fn getopts_error_json() {
+        let cmd_args = args(&["--error-format", "json", "--bogus"]);
+        let error = entrypoint(build_options().parse(&cmd_args)).unwrap_err();
+        let fields = parse_flat_json(&error.to_json());
+        assert_eq!(fields["code"], "getopts");
+        assert_eq!(fields["category"], "cmd_args");
+        assert!(fields["message"].contains("bogus"));
+        assert_eq!(fields["value"], "null");
+
+        let cmd_args = args(&["--error-format", "json", "--threads"]);
+        let error = entrypoint(build_options().parse(&cmd_args)).unwrap_err();
+        assert_eq!(parse_flat_json(&error.to_json())["code"], "getopts");
+    }
+
+    #[test]
+    fn invalid_threads_error_json() {
+        let cmd_args = args(&["-i", "in.db", "-o", "out.db", "-c", "cache", "--threads", "many"]);
+        let error = entrypoint(build_options().parse(&cmd_args)).unwrap_err();
+        let fields = parse_flat_json(&error.to_json());
+        assert_eq!(fields["code"], "invalid_threads_value");
+        assert_eq!(fields["category"], "cmd_args");
+        assert_eq!(fields["value"], "many");
+        assert_eq!(fields.len(), 4);
+    }
+
+    #[test]
+    fn zero_threads_error_json() {
+        let cmd_args = args(&["-i", "in.db", "-o", "out.db", "-c", "cache", "--threads", "0"]);
+        let error = entrypoint(build_options().parse(&cmd_args)).unwrap_err();
+        let fields = parse_flat_json(&error.to_json());
+        assert_eq!(fields["code"], "zero_threads");
+        assert_eq!(fields["category"], "cmd_args");
+    }
+
+    #[test]
+    fn output_too_large_error_json() {
+        let cmd_args = args(&["-i", "in.db", "-o", "out.db", "-c", "cache", "--output-align", "2G"]);
+        let error = entrypoint(build_options().parse(&cmd_args)).unwrap_err();
+        let fields = parse_flat_json(&error.to_json());
+        assert_eq!(fields["code"], "output_too_large");
+        assert_eq!(fields["value"], (2usize << 30).to_string());
+    }
+
+    #[test]
+    fn max_bytes_output_counts_padding() {
+        assert_eq!(check_output_size(64000, 64 << 10, 64 << 10).unwrap(), 1536);
+        assert_eq!(check_output_size(64000, 1, 64000).unwrap(), 0);
+        match check_output_size(64000, 64 << 10, 64000) {
+            Err(Error::CmdArgs(CmdArgsError::OutputTooLarge(65536, 64000))) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bytes_size_suffixes_and_overflow() {
+        assert_eq!(parse_bytes_size("512".to_string(), CmdArgsError::InvalidOutputAlignValue).unwrap(), 512);
+        assert_eq!(parse_bytes_size("4k".to_string(), CmdArgsError::InvalidOutputAlignValue).unwrap(), 4096);
+        assert_eq!(parse_bytes_size("2M".to_string(), CmdArgsError::InvalidOutputAlignValue).unwrap(), 2 << 20);
+        match parse_bytes_size("99999999999999G".to_string(), CmdArgsError::InvalidOutputAlignValue) {
+            Err(Error::CmdArgs(CmdArgsError::BytesSizeOverflow(ref v))) if v == "99999999999999G" => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match parse_bytes_size("7X".to_string(), CmdArgsError::InvalidOutputAlignValue) {
+            Err(Error::CmdArgs(CmdArgsError::InvalidOutputAlignValue(ref v, _))) if v == "7X" => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    struct FlakyWriter {
+        fail: bool,
+        data: Vec<u8>,
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.fail {
+                Err(io::Error::other("transient failure"))
+            } else {
+                self.data.write(buf)
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_padding_sizes() {
+        assert_eq!(output_padding(64000, 1), 0);
+        assert_eq!(output_padding(64000, 128), 0);
+        assert_eq!(output_padding(64000, 64 << 10), 1536);
+        assert_eq!(output_padding(6, 8), 2);
+    }
+
+    #[test]
+    fn padded_out_db_is_zero_filled() {
+        let out_values = [0xFFFF, 0x8001, 0xFFFF];
+        let padding = output_padding(out_values.len() * 2, 64 << 10);
+        let mut written = Vec::new();
+        let checksum = write_out_db(&mut written, &out_values, padding).unwrap();
+        assert_eq!(written.len(), 64 << 10);
+        assert_eq!(checksum, crc32_update(0, &written));
+        assert!(written[out_values.len() * 2 ..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn verify_out_db_catches_corruption() {
+        let db_filename = temp_path("verify.db");
+        let out_values = [7, 0, 0x1234];
+        let mut written = Vec::new();
+        write_out_db(&mut written, &out_values, 10).unwrap();
+        File::create(&db_filename).unwrap().write_all(&written).unwrap();
+        verify_out_db(&db_filename, &out_values, 10).unwrap();
+
+        let mut flipped = written.clone();
+        flipped[4] ^= 0x01;
+        File::create(&db_filename).unwrap().write_all(&flipped).unwrap();
+        match verify_out_db(&db_filename, &out_values, 10) {
+            Err(Error::OutDbVerifyMismatch(2, 0x1234, stored)) => assert!(stored != 0x1234),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        File::create(&db_filename).unwrap().write_all(&written[.. written.len() - 1]).unwrap();
+        match verify_out_db(&db_filename, &out_values, 10) {
+            Err(Error::OutDbVerifyPadding(10)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut dirty_padding = written.clone();
+        dirty_padding[out_values.len() * 2 + 3] = 0x80;
+        File::create(&db_filename).unwrap().write_all(&dirty_padding).unwrap();
+        match verify_out_db(&db_filename, &out_values, 10) {
+            Err(Error::OutDbVerifyPadding(10)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        fs::remove_file(&db_filename).unwrap();
+    }
+
+    fn write_with_failures(failures: u32, retries: u32) -> (Result<u32, Error>, u32, Vec<u8>) {
+        let mut attempts = 0;
+        let mut written = Vec::new();
+        let result = retry_io(retries, 0, "test write", true, || {
+            attempts += 1;
+            let mut writer = FlakyWriter { fail: attempts <= failures, data: Vec::new() };
+            let checksum = try!(write_out_db(&mut writer, &[1, 2, 0xABCD], 2));
+            written = writer.data;
+            Ok(checksum)
+        });
+        (result, attempts, written)
+    }
+
+    #[test]
+    fn retry_io_recovers_from_transient_write_failures() {
+        let (result, attempts, written) = write_with_failures(2, 3);
+        let mut expected = Vec::new();
+        for &value in &[1u16, 2, 0xABCD, 0] {
+            expected.extend_from_slice(&value.to_ne_bytes());
+        }
+        assert_eq!(result.unwrap(), crc32_update(0, &expected));
+        assert_eq!(attempts, 3);
+        assert_eq!(written, expected);
+
+        match write_with_failures(2, 1) {
+            (Err(Error::OutDbWrite(_)), 2, _) => (),
+            (other, attempts, _) => panic!("unexpected result after {} attempts: {:?}", attempts, other),
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("hola_compiler_test_{}_{}", process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32_update(0, b"123456789"), 0xcbf43926);
+        assert_eq!(crc32_update(crc32_update(0, b"1234"), b"56789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn verify_checksum_detects_flipped_byte() {
+        let db_filename = temp_path("checksum.db");
+        let data: Vec<u8> = (0 .. 64u8).collect();
+        File::create(&db_filename).unwrap().write_all(&data).unwrap();
+        write_checksum_file(&db_filename, crc32_update(0, &data), true).unwrap();
+        verify_checksum(&db_filename, true, false).unwrap();
+
+        let mut corrupted = data.clone();
+        corrupted[10] ^= 0x01;
+        File::create(&db_filename).unwrap().write_all(&corrupted).unwrap();
+        match verify_checksum(&db_filename, true, false) {
+            Err(Error::ChecksumMismatch(expected, actual)) => {
+                assert_eq!(expected, crc32_update(0, &data));
+                assert_eq!(actual, crc32_update(0, &corrupted));
+            },
+            other => panic!("unexpected result: {:?}", other),
+        }
+        fs::remove_file(&db_filename).unwrap();
+        fs::remove_file(checksum_filename(&db_filename)).unwrap();
+    }
+
+    #[test]
+    fn seed_sample_read_error_reports_record_index() {
+        let record_size = 8 * 4;
+        let mut in_db = Vec::new();
+        for hash in 1 .. 3 * 8 + 1 {
+            in_db.write_i32::<NativeEndian>(hash).unwrap();
+        }
+        let mut read_buf = vec![0; record_size];
+
+        let seed_sample = read_seed_sample(&mut Cursor::new(&in_db), &mut read_buf, 3, 1, 4).unwrap();
+        assert_eq!(seed_sample, vec![5, 6, 7, 8, 13, 14, 15, 16, 21, 22, 23, 24]);
+
+        in_db.truncate(2 * record_size + 5);
+        match read_seed_sample(&mut Cursor::new(&in_db), &mut read_buf, 3, 0, 4) {
+            Err(Error::InDbReadAt(2, ref e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn create_versioned_picks_next_free_suffix() {
+        let db_filename = temp_path("versioned.db");
+        let (first_filename, _) = create_versioned(&db_filename).unwrap();
+        let (second_filename, _) = create_versioned(&db_filename).unwrap();
+        assert_eq!(first_filename, format!("{}.1", db_filename));
+        assert_eq!(second_filename, format!("{}.2", db_filename));
+        fs::remove_file(first_filename).unwrap();
+        fs::remove_file(second_filename).unwrap();
+    }
+
+    #[test]
+    fn progress_json_lines() {
+        let search = selftest_search(2, None);
+        let divs_total = search.chunk_limit * search.chunk_size;
+        let in_db = selftest_db(search.words_count, search.record_len);
+        let mut cache = Cursor::new(Vec::new());
+        // the second run is served entirely from the cache filled by the first one
+        for _ in 0 .. 2 {
+            cache.set_position(0);
+            let mut progress = Vec::new();
+            let (divs_found, _, _) = search.run(&mut Cursor::new(&in_db), &mut cache, Some(&mut progress), Instant::now()).unwrap();
+            assert_eq!(cache.get_ref().len(), divs_total * 4);
+
+            let lines: Vec<HashMap<String, String>> = String::from_utf8(progress).unwrap().lines().map(parse_flat_json).collect();
+            let divs_done: Vec<usize> = lines.iter().map(|fields| fields["divs_done"].parse().unwrap()).collect();
+            let expected: Vec<usize> = (0 .. search.chunk_limit).map(|chunk_index| chunk_index * search.chunk_size).chain(Some(divs_total)).collect();
+            assert_eq!(divs_done, expected);
+            assert_eq!(*divs_done.last().unwrap(), divs_found.len());
+            let elapsed: Vec<u128> = lines.iter().map(|fields| fields["elapsed_ms"].parse().unwrap()).collect();
+            assert!(elapsed.windows(2).all(|pair| pair[0] <= pair[1]));
+            assert!(lines.iter().all(|fields| fields.len() == 3 && fields["divs_total"] == divs_total.to_string()));
+        }
+    }
+
+    #[test]
+    fn selftest_finds_smallest_divs() {
+        selftest(8, None, true, false).unwrap();
+
+        let search = selftest_search(8, None);
+        let in_db = selftest_db(search.words_count, search.record_len);
+        let (divs_found, min_div, max_div) =
+            search.run(&mut Cursor::new(&in_db), &mut Cursor::new(Vec::new()), None::<&mut io::Stderr>, Instant::now()).unwrap();
+        let mut in_db_r = Cursor::new(&in_db);
+        let mut hashes = Vec::new();
+        while let Ok(hash) = in_db_r.read_i32::<NativeEndian>() {
+            hashes.push(hash);
+        }
+        let expected: Vec<i32> = (0 .. search.chunk_limit * search.chunk_size).map(|index| {
+            (1 ..).find(|&div| (0 .. search.words_count).all(|word| hashes[word * search.record_len + index] % div != 0)).unwrap()
+        }).collect();
+        assert_eq!(divs_found, expected);
+        assert_eq!(min_div, *expected.iter().min().unwrap());
+        assert_eq!(max_div, *expected.iter().max().unwrap());
+    }
+
+    #[test]
+    fn run_summary_json_keys() {
+        let divs_found = [3, 0, 5, 4];
+        let summary = RunSummary {
+            in_db_filename: "in \"db\".bin",
+            in_db_size: 360000,
+            words_count: 2,
+            out_db_filename: "out.db",
+            calc_cache_filename: "cache",
+            threads_count: 4,
+            div_start: 1,
+            divs_found: &divs_found,
+            min_div: 3,
+            max_div: 5,
+            elapsed_ms: 17,
+        };
+        let fields = parse_flat_json(&summary.to_json());
+        for key in &["in_db", "in_db_size", "words", "out_db", "calc_cache", "threads", "div_start",
+                     "divs", "zero_divs", "min_div", "max_div", "elapsed_ms"] {
+            assert!(fields.contains_key(*key), "missing key {}", key);
+        }
+        assert_eq!(fields["in_db"], "in \"db\".bin");
+        assert_eq!(fields["divs"], "4");
+        assert_eq!(fields["zero_divs"], "1");
+    }
+}